@@ -1,12 +1,13 @@
 use super::bits_trait::ToBits;
 use crate::middleware::usbboot::FlashingError;
 use crate::middleware::{
-    app_persistency::ApplicationPersistency, event_listener::EventListener,
+    app_persistency::ApplicationPersistency, event_listener::EventListener, fw_update,
     pin_controller::PinController, usbboot, NodeId, UsbMode, UsbRoute,
 };
 use anyhow::{ensure, Context};
 use evdev::Key;
 use log::debug;
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use std::process::Command;
 use std::sync::Arc;
@@ -15,6 +16,7 @@ use tokio::sync::mpsc::Receiver;
 use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
 
 /// Stores which slots are actually used. This information is used to determine
 /// for instance, which nodes need to be powered on, when such command is given
@@ -23,6 +25,35 @@ const NODE_ENABLED_KEY: &str = "node_enabled";
 const USB_NODE_KEY: &str = "usb_node";
 const USB_ROUTE_KEY: &str = "usb_route";
 const USB_MODE_KEY: &str = "usb_mode";
+/// how long `flash_node` waits for a matching USB device to appear before failing.
+const DEVICE_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+/// prefix for the per-node key under which the most recent `FlashRecord` is stored.
+const LAST_FLASH_KEY_PREFIX: &str = "last_flash_node_";
+
+fn last_flash_key(node: NodeId) -> String {
+    format!("{}{}", LAST_FLASH_KEY_PREFIX, node as u8)
+}
+
+/// The lifecycle of a single flash of a node, from the moment the image has been written up to
+/// the node confirming it actually booted. Mirrors the get-state/mark-booted bookkeeping a
+/// firmware updater would keep so operators can tell a written-but-never-booted module from one
+/// that came up fine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FlashState {
+    Flashed,
+    Verified,
+    Booted,
+    Failed,
+}
+
+/// Persisted record of the most recent flash of a node, keyed like [`NODE_ENABLED_KEY`] /
+/// [`USB_NODE_KEY`] but per-node via [`last_flash_key`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlashRecord {
+    pub checksum: String,
+    pub length: u64,
+    pub state: FlashState,
+}
 
 #[derive(Debug)]
 pub struct BmcApplication {
@@ -201,31 +232,65 @@ impl BmcApplication {
         Ok(())
     }
 
-    async fn set_usb_mode(&self, node: NodeId, mode: UsbMode) -> anyhow::Result<()> {
+    pub(crate) async fn set_usb_mode(&self, node: NodeId, mode: UsbMode) -> anyhow::Result<()> {
         let prev_mode = self.app_db.get::<u8>(USB_MODE_KEY).await.unwrap_or(0b1111);
         let new_mode = self.pin_controller.set_usb_mode(node, mode, prev_mode)?;
 
         self.app_db.set(USB_MODE_KEY, new_mode).await
     }
 
+    /// Exposes the pin controller to `FwUpdate` drivers, which need to toggle module-specific
+    /// boot-select pins (e.g. RPIBOOT) as part of `reboot_to_msd`.
+    pub(crate) fn pin_controller(&self) -> &PinController {
+        &self.pin_controller
+    }
+
     pub async fn rtl_reset(&self) -> anyhow::Result<()> {
         self.pin_controller.rtl_reset().await.context("rtl error")
     }
 
+    /// Marks the most recent flash record for `node` as [`FlashState::Booted`], confirming that
+    /// the image written by `flash_node` actually came up. Call this once the node is observed
+    /// alive, e.g. in response to a heartbeat or health-check from the node itself.
+    pub async fn confirm_node_booted(&self, node: NodeId) -> anyhow::Result<()> {
+        let key = last_flash_key(node);
+        // `ApplicationPersistency::get` has no way to tell "no record" apart from a real lookup
+        // failure (same as every other app_db read in this file), so we can't propagate the
+        // latter without also breaking the legitimate "node was never flashed" case. Until the
+        // persistency API can express that distinction, treat any failure here as nothing to
+        // confirm rather than claiming we've surfaced it.
+        if let Ok(mut record) = self.app_db.get::<FlashRecord>(&key).await {
+            record.state = FlashState::Booted;
+            self.app_db.set(key, record).await?;
+        }
+        Ok(())
+    }
+
     pub fn flash_node(
         self: Arc<BmcApplication>,
         node: NodeId,
         image_path: PathBuf,
+        cancel: CancellationToken,
     ) -> (JoinHandle<anyhow::Result<()>>, Receiver<FlashProgress>) {
         let (sender, receiver) = tokio::sync::mpsc::channel(64);
         let inner = async move {
-            // arbitrary number, this sleep may not even be required
-            let reboot_delay = Duration::from_millis(500);
             let mut progress_state = FlashProgress {
                 message: String::new(),
                 status: FlashStatus::Idle,
             };
 
+            macro_rules! bail_if_cancelled {
+                () => {
+                    if cancel.is_cancelled() {
+                        self.restore_host_usb(node).await?;
+                        progress_state.message = String::from("Flash cancelled");
+                        progress_state.status = FlashStatus::Cancelled;
+                        sender.send(progress_state).await?;
+                        return Ok(());
+                    }
+                };
+            }
+
             progress_state.message = format!("Powering off node {}...", node as u8 + 1);
             progress_state.status = FlashStatus::Progress {
                 read_percent: 0,
@@ -233,68 +298,112 @@ impl BmcApplication {
                 est_seconds: u64::MAX,
             };
             sender.send(progress_state.clone()).await?;
+            bail_if_cancelled!();
 
-            self.activate_slot(node, false).await?;
-            self.pin_controller.clear_usb_boot()?;
-
-            sleep(reboot_delay).await;
-
-            self.pin_controller.select_usb(node)?;
-            self.pin_controller.set_usb_boot(node)?;
-            self.pin_controller.set_usb_route(UsbRoute::BMC)?;
-
-            self.set_usb_mode(node, UsbMode::Device).await?;
-
-            progress_state.message = String::from("Prerequisite settings toggled, powering on...");
-            sender.send(progress_state.clone()).await?;
-
-            self.activate_slot(node, true).await?;
-
-            sleep(Duration::from_secs(2)).await;
-
-            progress_state.message = String::from("Checking for presence of a USB device...");
-            sender.send(progress_state.clone()).await?;
-
-            let allowed_devices = [
-                (0x0a5c, 0x2711), // Raspberry Pi Compute module 4
-            ];
-            usbboot::check_only_one_device_present(&allowed_devices)?;
-
-            progress_state.message = String::from("Rebooting as a USB mass storage device...");
-            sender.send(progress_state.clone()).await?;
-
-            usbboot::boot_node_to_msd(node)?;
-
-            sleep(Duration::from_secs(3)).await;
+            let driver = fw_update::detect(&self, node, &sender).await?;
+            bail_if_cancelled!();
 
             progress_state.message = String::from("Checking for presence of a device file...");
             sender.send(progress_state.clone()).await?;
+            bail_if_cancelled!();
 
-            let allowed_vendors = ["RPi-MSD-"];
-            let device_path = usbboot::get_device_path(&allowed_vendors).await?;
+            let device_path = driver.await_block_device().await?;
+
+            if let Ok(prev) = self.app_db.get::<FlashRecord>(&last_flash_key(node)).await {
+                if matches!(prev.state, FlashState::Flashed | FlashState::Verified) {
+                    progress_state.message = format!(
+                        "Warning: previous flash of node {} was never confirmed booted (state: {:?})",
+                        node as u8 + 1,
+                        prev.state
+                    );
+                    sender.send(progress_state.clone()).await?;
+                }
+            }
 
             progress_state.message = format!("Writing {:?} to {:?}", image_path, device_path);
             sender.send(progress_state.clone()).await?;
+            bail_if_cancelled!();
 
             let (img_len, img_checksum) =
-                usbboot::write_to_device(image_path, &device_path, &sender).await?;
+                match usbboot::write_to_device(image_path, &device_path, &sender, &cancel).await {
+                    Ok(result) => result,
+                    Err(e)
+                        if matches!(
+                            e.downcast_ref::<FlashingError>(),
+                            Some(FlashingError::Cancelled)
+                        ) =>
+                    {
+                        self.restore_host_usb(node).await?;
+                        progress_state.message = String::from("Flash cancelled");
+                        progress_state.status = FlashStatus::Cancelled;
+                        sender.send(progress_state).await?;
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        self.app_db
+                            .set(
+                                last_flash_key(node),
+                                FlashRecord {
+                                    checksum: String::new(),
+                                    length: 0,
+                                    state: FlashState::Failed,
+                                },
+                            )
+                            .await?;
+                        return Err(e);
+                    }
+                };
+
+            self.app_db
+                .set(
+                    last_flash_key(node),
+                    FlashRecord {
+                        checksum: img_checksum.clone(),
+                        length: img_len,
+                        state: FlashState::Flashed,
+                    },
+                )
+                .await?;
 
             progress_state.message = String::from("Verifying checksum...");
             sender.send(progress_state.clone()).await?;
-
-            usbboot::verify_checksum(img_checksum, img_len, &device_path, &sender).await?;
+            bail_if_cancelled!();
+
+            if let Err(e) =
+                usbboot::verify_checksum(img_checksum.clone(), img_len, &device_path, &sender)
+                    .await
+            {
+                self.app_db
+                    .set(
+                        last_flash_key(node),
+                        FlashRecord {
+                            checksum: img_checksum,
+                            length: img_len,
+                            state: FlashState::Failed,
+                        },
+                    )
+                    .await?;
+                return Err(e);
+            }
+
+            self.app_db
+                .set(
+                    last_flash_key(node),
+                    FlashRecord {
+                        checksum: img_checksum,
+                        length: img_len,
+                        state: FlashState::Verified,
+                    },
+                )
+                .await?;
 
             progress_state.message = String::from("Flashing successful, restarting device...");
             sender.send(progress_state.clone()).await?;
 
-            self.activate_slot(node, false).await?;
-            self.usb_mode(UsbMode::Host, node).await?;
-
-            sleep(reboot_delay).await;
-
-            self.activate_slot(node, true).await?;
+            self.restore_host_usb(node).await?;
 
             progress_state.message = String::from("Done");
+            progress_state.status = FlashStatus::Done;
             sender.send(progress_state).await?;
 
             Ok(())
@@ -302,6 +411,17 @@ impl BmcApplication {
 
         (tokio::spawn(inner), receiver)
     }
+
+    /// Restores `node` to a powered-on, host-USB state. Used both after a successful flash and
+    /// when a flash is cancelled, so a node is never left sitting in device mode, powered off.
+    async fn restore_host_usb(&self, node: NodeId) -> anyhow::Result<()> {
+        self.activate_slot(node, false).await?;
+        self.usb_mode(UsbMode::Host, node).await?;
+
+        sleep(Duration::from_millis(500)).await;
+
+        self.activate_slot(node, true).await
+    }
 }
 
 fn reboot() -> anyhow::Result<()> {
@@ -319,6 +439,9 @@ pub enum FlashStatus {
         est_seconds: u64,
     },
     Error(FlashingError),
+    /// The flash was cancelled before completion; the node has been restored to a powered-on,
+    /// host-USB state, same as after `Done`.
+    Cancelled,
     Done,
 }
 
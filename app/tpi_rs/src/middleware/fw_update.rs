@@ -0,0 +1,122 @@
+use crate::app::bmc_application::{BmcApplication, FlashProgress, FlashStatus};
+use crate::middleware::usb_monitor::{self, DeviceHandle, DeviceMatcher};
+use crate::middleware::{NodeId, UsbMode, UsbRoute};
+use async_trait::async_trait;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tokio::time::sleep;
+
+/// How long `detect`/`await_block_device` wait for their device to appear before giving up.
+const DEVICE_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+/// delay to let a module's bootrom latch its boot-select pins before it's powered back on.
+const BOOT_PIN_SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// Drives the module-specific steps of a firmware flash: getting the node into USB mass-storage
+/// mode (which, for a module like the CM4, means asserting its own boot-select pins) and waiting
+/// for the resulting block device to appear. `flash_node` drives the generic
+/// detect/write/verify/restore sequence around this; a new compute module only needs a new `impl
+/// FwUpdate` plus a `SUPPORTED_DEVICES` row, not edits scattered through `flash_node` itself.
+#[async_trait]
+pub trait FwUpdate: Send + Sync {
+    /// Power-cycles `node` into USB mass-storage mode: powers it off, asserts whatever
+    /// boot-select pins this module needs, routes its USB to the BMC in device mode, then powers
+    /// it back on so its bootrom enumerates as mass storage. The bus routing is generic; only
+    /// [`assert_boot_pins`](Self::assert_boot_pins) differs between module types.
+    async fn reboot_to_msd(&self, app: &BmcApplication, node: NodeId) -> anyhow::Result<()> {
+        app.activate_slot(node, false).await?;
+        self.assert_boot_pins(app, node)?;
+
+        sleep(BOOT_PIN_SETTLE_DELAY).await;
+
+        app.pin_controller().select_usb(node)?;
+        app.pin_controller().set_usb_route(UsbRoute::BMC)?;
+        app.set_usb_mode(node, UsbMode::Device).await?;
+
+        app.activate_slot(node, true).await
+    }
+
+    /// Asserts (or clears) whatever module-specific boot-select pins put this module's bootrom
+    /// into mass-storage mode. Defaults to a no-op for modules that don't need any, e.g. ones
+    /// that always expose mass storage once simply put in USB device mode.
+    fn assert_boot_pins(&self, _app: &BmcApplication, _node: NodeId) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Waits for the mass-storage block device to materialize and returns its path.
+    async fn await_block_device(&self) -> anyhow::Result<PathBuf>;
+}
+
+/// Raspberry Pi Compute Module 4, flashed over its USB-C "device" port via RPIBOOT.
+struct RpiCm4;
+
+#[async_trait]
+impl FwUpdate for RpiCm4 {
+    fn assert_boot_pins(&self, app: &BmcApplication, node: NodeId) -> anyhow::Result<()> {
+        app.pin_controller().clear_usb_boot()?;
+        app.pin_controller().set_usb_boot(node)?;
+        Ok(())
+    }
+
+    async fn await_block_device(&self) -> anyhow::Result<PathBuf> {
+        const ALLOWED_VENDORS: &[&str] = &["RPi-MSD-"];
+        let handle = usb_monitor::wait_for_device(
+            DeviceMatcher::BlockDeviceLabel(ALLOWED_VENDORS.to_vec()),
+            DEVICE_WAIT_TIMEOUT,
+        )
+        .await?;
+
+        match handle {
+            DeviceHandle::BlockDevice(path) => Ok(path),
+            DeviceHandle::UsbId(..) => {
+                unreachable!("BlockDeviceLabel matcher only ever resolves to a BlockDevice handle")
+            }
+        }
+    }
+}
+
+/// USB vendor/product identity each module enumerates with once in mass-storage mode, mapped to
+/// the driver that knows how to get it there. Add a module by adding a row here and a small
+/// `FwUpdate` impl.
+const SUPPORTED_DEVICES: &[((u16, u16), fn() -> Box<dyn FwUpdate>)] = &[
+    ((0x0a5c, 0x2711), || Box::new(RpiCm4)), // Raspberry Pi Compute Module 4
+];
+
+/// Tries each supported module's `reboot_to_msd` against `node` in turn until one of them
+/// actually enumerates, and returns that driver. Which module is physically inserted isn't known
+/// up front — asserting boot-select pins needs a driver, but picking a driver needs to know what's
+/// there — so this is what makes "enumerate the USB device present on the selected node, look up
+/// its driver" work without `flash_node` having to know about module types at all.
+pub async fn detect(
+    app: &BmcApplication,
+    node: NodeId,
+    sender: &Sender<FlashProgress>,
+) -> anyhow::Result<Box<dyn FwUpdate>> {
+    for (usb_id, ctor) in SUPPORTED_DEVICES {
+        let driver = ctor();
+
+        let _ = sender
+            .send(FlashProgress {
+                message: String::from("Rebooting as a USB mass storage device..."),
+                status: FlashStatus::Progress {
+                    read_percent: 0,
+                    est_minutes: u64::MAX,
+                    est_seconds: u64::MAX,
+                },
+            })
+            .await;
+        driver.reboot_to_msd(app, node).await?;
+
+        if usb_monitor::wait_for_device(DeviceMatcher::UsbId(vec![*usb_id]), DEVICE_WAIT_TIMEOUT)
+            .await
+            .is_ok()
+        {
+            return Ok(driver);
+        }
+    }
+
+    anyhow::bail!(
+        "no supported firmware-update driver responded for node {}",
+        node as u8 + 1
+    )
+}
@@ -0,0 +1,104 @@
+use anyhow::{anyhow, Context};
+use std::convert::TryInto;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_stream::StreamExt;
+use tokio_udev::{AsyncMonitorSocket, Device, Enumerator, EventType, MonitorBuilder};
+
+/// What [`wait_for_device`] should resolve on.
+#[derive(Debug, Clone)]
+pub enum DeviceMatcher {
+    /// A USB device with one of the given `(vendor_id, product_id)` pairs appears on the bus.
+    UsbId(Vec<(u16, u16)>),
+    /// A block device whose `ID_VENDOR` starts with one of the given prefixes materializes, e.g.
+    /// a node that just rebooted into USB mass-storage mode.
+    BlockDeviceLabel(Vec<&'static str>),
+}
+
+/// What actually matched a [`DeviceMatcher`].
+#[derive(Debug, Clone)]
+pub enum DeviceHandle {
+    UsbId(u16, u16),
+    BlockDevice(PathBuf),
+}
+
+/// Resolves the moment a device matching `matcher` appears on the bus, instead of the fixed
+/// sleeps `flash_node` used to rely on. Devices already present when called resolve immediately;
+/// otherwise this awaits udev hotplug events and fails fast with a timeout error if nothing
+/// matching shows up within `wait_for`.
+pub async fn wait_for_device(
+    matcher: DeviceMatcher,
+    wait_for: Duration,
+) -> anyhow::Result<DeviceHandle> {
+    if let Some(handle) = scan_existing(&matcher)? {
+        return Ok(handle);
+    }
+
+    let monitor: AsyncMonitorSocket = MonitorBuilder::new()?
+        .match_subsystem(subsystem_for(&matcher))?
+        .listen()?
+        .try_into()?;
+
+    timeout(wait_for, watch(monitor, matcher))
+        .await
+        .context("timed out waiting for a matching USB device")?
+}
+
+/// The udev subsystem a matcher's device shows up under: `usb` for the module's BMC-side USB
+/// identity, `block` for the mass-storage device node it exposes once rebooted into MSD mode.
+fn subsystem_for(matcher: &DeviceMatcher) -> &'static str {
+    match matcher {
+        DeviceMatcher::UsbId(_) => "usb",
+        DeviceMatcher::BlockDeviceLabel(_) => "block",
+    }
+}
+
+fn scan_existing(matcher: &DeviceMatcher) -> anyhow::Result<Option<DeviceHandle>> {
+    let mut enumerator = Enumerator::new()?;
+    enumerator.match_subsystem(subsystem_for(matcher))?;
+    for device in enumerator.scan_devices()? {
+        if let Some(handle) = match_device(&device, matcher) {
+            return Ok(Some(handle));
+        }
+    }
+    Ok(None)
+}
+
+async fn watch(
+    mut monitor: AsyncMonitorSocket,
+    matcher: DeviceMatcher,
+) -> anyhow::Result<DeviceHandle> {
+    while let Some(event) = monitor.next().await {
+        let event = event?;
+        if event.event_type() != EventType::Add {
+            continue;
+        }
+
+        if let Some(handle) = match_device(event.device(), &matcher) {
+            return Ok(handle);
+        }
+    }
+
+    Err(anyhow!("udev monitor closed while waiting for a matching device"))
+}
+
+fn match_device(device: &Device, matcher: &DeviceMatcher) -> Option<DeviceHandle> {
+    match matcher {
+        DeviceMatcher::UsbId(ids) => {
+            let vendor_id =
+                u16::from_str_radix(device.property_value("ID_VENDOR_ID")?.to_str()?, 16).ok()?;
+            let product_id =
+                u16::from_str_radix(device.property_value("ID_MODEL_ID")?.to_str()?, 16).ok()?;
+            ids.contains(&(vendor_id, product_id))
+                .then_some(DeviceHandle::UsbId(vendor_id, product_id))
+        }
+        DeviceMatcher::BlockDeviceLabel(prefixes) => {
+            let vendor = device.property_value("ID_VENDOR")?.to_str()?;
+            if !prefixes.iter().any(|prefix| vendor.starts_with(prefix)) {
+                return None;
+            }
+            device.devnode().map(|p| DeviceHandle::BlockDevice(p.to_path_buf()))
+        }
+    }
+}
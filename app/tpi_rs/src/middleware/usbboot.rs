@@ -0,0 +1,104 @@
+use crate::app::bmc_application::{FlashProgress, FlashStatus};
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
+
+/// Chunk size used for both the write and the verification read-back.
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Error)]
+pub enum FlashingError {
+    #[error("flash cancelled")]
+    Cancelled,
+    #[error("checksum mismatch between written image and device")]
+    ChecksumMismatch,
+}
+
+/// Streams `image_path` to `device_path` in `CHUNK_SIZE` chunks, reporting progress on `sender`
+/// and hashing the data as it goes. Checked for cancellation on every chunk boundary so a flash
+/// can be aborted mid-write instead of only between pipeline stages.
+pub async fn write_to_device(
+    image_path: PathBuf,
+    device_path: &Path,
+    sender: &Sender<FlashProgress>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<(u64, String)> {
+    let mut src = File::open(&image_path)
+        .await
+        .with_context(|| format!("failed to open image {:?}", image_path))?;
+    let mut dst = File::create(device_path)
+        .await
+        .with_context(|| format!("failed to open device {:?}", device_path))?;
+
+    let total_len = src.metadata().await?.len().max(1);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut written = 0u64;
+
+    loop {
+        if cancel.is_cancelled() {
+            return Err(FlashingError::Cancelled.into());
+        }
+
+        let read = src.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+
+        dst.write_all(&buf[..read]).await?;
+        hasher.update(&buf[..read]);
+        written += read as u64;
+
+        let _ = sender
+            .send(FlashProgress {
+                message: format!("Writing {:?} to {:?}", image_path, device_path),
+                status: FlashStatus::Progress {
+                    read_percent: written * 100 / total_len,
+                    est_minutes: 0,
+                    est_seconds: 0,
+                },
+            })
+            .await;
+    }
+
+    dst.flush().await?;
+
+    Ok((written, format!("{:x}", hasher.finalize())))
+}
+
+/// Reads `expected_len` bytes back from `device_path` and confirms its hash matches
+/// `expected_checksum`, the one `write_to_device` computed while writing.
+pub async fn verify_checksum(
+    expected_checksum: String,
+    expected_len: u64,
+    device_path: &Path,
+    _sender: &Sender<FlashProgress>,
+) -> anyhow::Result<()> {
+    let mut file = File::open(device_path)
+        .await
+        .with_context(|| format!("failed to open device {:?}", device_path))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut read_total = 0u64;
+
+    while read_total < expected_len {
+        let want = buf.len().min((expected_len - read_total) as usize);
+        let read = file.read(&mut buf[..want]).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        read_total += read as u64;
+    }
+
+    if format!("{:x}", hasher.finalize()) != expected_checksum {
+        return Err(FlashingError::ChecksumMismatch.into());
+    }
+
+    Ok(())
+}